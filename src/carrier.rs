@@ -0,0 +1,167 @@
+use crate::error::Error;
+use itertools::Itertools;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Format-specific state needed to write the carrier back out once the
+/// payload has been embedded into `Image::bytes`.
+pub enum Format {
+    Png { alpha: Option<Vec<u8>>, info: png::OutputInfo },
+    Wav { spec: hound::WavSpec },
+}
+
+/// A carrier loaded into memory: a flat byte stream the payload is spread
+/// across (one byte per PNG colour channel, with any alpha channel kept aside
+/// in `Format::Png` and left untouched, or one little-endian byte
+/// per WAV sample byte), plus whatever's needed to rebuild the original file.
+pub struct Image {
+    pub bytes: Vec<u8>,
+    pub format: Format,
+}
+
+/// A carrier file type that can be read into an [`Image`] and written back
+/// out losslessly once its `bytes` have been modified in place.
+pub trait Carrier {
+    fn read_samples(file: &File) -> Result<Image, Error>;
+    fn write_samples(output: &str, image: &Image, bytes: &[u8]) -> Result<(), Error>;
+}
+
+pub struct Png;
+
+impl Carrier for Png {
+    fn read_samples(file: &File) -> Result<Image, Error> {
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+
+        let buffersize = reader.output_buffer_size();
+        let mut bytes = vec![0; buffersize];
+        let info = reader.next_frame(&mut bytes)?;
+
+        let mut alpha = None;
+        if info.color_type == png::ColorType::Rgba {
+            alpha = Some(
+                bytes
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| i % 4 == 3)
+                    .map(|(_, e)| *e)
+                    .collect(),
+            );
+            bytes = bytes
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| i % 4 != 3)
+                .map(|(_, e)| e)
+                .collect();
+        }
+        Ok(Image {
+            bytes,
+            format: Format::Png { alpha, info },
+        })
+    }
+
+    fn write_samples(output: &str, image: &Image, bytes: &[u8]) -> Result<(), Error> {
+        let Format::Png { alpha, info } = &image.format else {
+            unreachable!("Png::write_samples called with a non-PNG Image")
+        };
+
+        let path = Path::new(output);
+        let file = File::create(path)?;
+        let w = BufWriter::new(file);
+
+        let mut encoder = png::Encoder::new(w, info.width, info.height);
+        encoder.set_color(info.color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+
+        writer.write_image_data(&add_alpha(bytes.to_vec(), alpha.clone()))?;
+        Ok(())
+    }
+}
+
+fn add_alpha(bytes: Vec<u8>, alpha: Option<Vec<u8>>) -> Vec<u8> {
+    match alpha {
+        Some(alpha) => bytes
+            .into_iter()
+            .chunks(3)
+            .into_iter()
+            .interleave(alpha.into_iter().chunks(1).into_iter())
+            .flatten()
+            .collect(),
+        None => bytes,
+    }
+}
+
+pub struct Wav;
+
+impl Carrier for Wav {
+    fn read_samples(file: &File) -> Result<Image, Error> {
+        let mut reader = hound::WavReader::new(file)?;
+        let spec = reader.spec();
+        let bytes = reader
+            .samples::<i16>()
+            .map(|s| s.map(i16::to_le_bytes))
+            .collect::<Result<Vec<[u8; 2]>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(Image {
+            bytes,
+            format: Format::Wav { spec },
+        })
+    }
+
+    fn write_samples(output: &str, image: &Image, bytes: &[u8]) -> Result<(), Error> {
+        let Format::Wav { spec } = &image.format else {
+            unreachable!("Wav::write_samples called with a non-WAV Image")
+        };
+
+        let mut writer = hound::WavWriter::create(output, *spec)?;
+        for chunk in bytes.chunks(2) {
+            writer.write_sample(i16::from_le_bytes([chunk[0], chunk[1]]))?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+/// Which carrier implementation to use, either picked explicitly via
+/// `--format` or inferred from the file extension.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Png,
+    Wav,
+}
+
+impl Kind {
+    pub fn parse(value: &str) -> Option<Kind> {
+        match value.to_lowercase().as_str() {
+            "png" => Some(Kind::Png),
+            "wav" => Some(Kind::Wav),
+            _ => None,
+        }
+    }
+
+    pub fn from_extension(path: &str) -> Kind {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("wav") => Kind::Wav,
+            _ => Kind::Png,
+        }
+    }
+}
+
+pub fn load(file: &File, kind: Kind) -> Result<Image, Error> {
+    match kind {
+        Kind::Png => Png::read_samples(file),
+        Kind::Wav => Wav::read_samples(file),
+    }
+}
+
+pub fn save(output: &str, image: &Image, bytes: &[u8], kind: Kind) -> Result<(), Error> {
+    match kind {
+        Kind::Png => Png::write_samples(output, image, bytes),
+        Kind::Wav => Wav::write_samples(output, image, bytes),
+    }
+}
@@ -0,0 +1,21 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// DEFLATEs `data`, returning `None` if that doesn't actually shrink it (small
+/// or already-compressed payloads), so callers can fall back to the raw bytes.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).expect("in-memory deflate failed");
+    let compressed = encoder.finish().expect("in-memory deflate failed");
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Reverses [`compress`].
+pub fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoder = DeflateDecoder::new(data);
+    let mut out = Vec::new();
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
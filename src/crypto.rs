@@ -0,0 +1,54 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use rand::RngCore;
+
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+
+fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .expect("argon2 key derivation failed");
+    key
+}
+
+/// Encrypts `data` with ChaCha20-Poly1305 under a key derived from `password`
+/// and a fresh random salt, returning `salt || nonce || ciphertext || tag`.
+/// The salt is random per message so that two messages encrypted under the
+/// same password never derive the same key.
+pub fn encrypt(data: &[u8], password: &str) -> Vec<u8> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(password, &salt);
+    let cipher = ChaCha20Poly1305::new((&key).into());
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let ciphertext = cipher
+        .encrypt(nonce, data)
+        .expect("chacha20poly1305 encryption failed");
+
+    salt.iter()
+        .chain(&nonce_bytes)
+        .copied()
+        .chain(ciphertext)
+        .collect()
+}
+
+/// Reverses [`encrypt`], returning `None` if the blob is malformed or the
+/// AEAD tag does not verify (wrong password or corrupted carrier).
+pub fn decrypt(blob: &[u8], password: &str) -> Option<Vec<u8>> {
+    if blob.len() < SALT_LEN + NONCE_LEN {
+        return None;
+    }
+    let salt = &blob[..SALT_LEN];
+    let nonce_bytes = &blob[SALT_LEN..SALT_LEN + NONCE_LEN];
+    let key = derive_key(password, salt);
+    let cipher = ChaCha20Poly1305::new((&key).into());
+    let nonce = Nonce::from_slice(nonce_bytes);
+    cipher.decrypt(nonce, &blob[SALT_LEN + NONCE_LEN..]).ok()
+}
@@ -0,0 +1,33 @@
+const POLY: u32 = 0xEDB8_8320;
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32 (IEEE 802.3) digest of `data`, used to detect a corrupted or
+/// partially-overwritten carrier before trusting the extracted payload.
+pub fn digest(data: &[u8]) -> [u8; 4] {
+    let table = crc32_table();
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        let index = ((crc ^ *byte as u32) & 0xFF) as usize;
+        crc = (crc >> 8) ^ table[index];
+    }
+    (crc ^ 0xFFFF_FFFF).to_be_bytes()
+}
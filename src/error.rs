@@ -0,0 +1,62 @@
+use std::fmt;
+
+/// Crate-wide error type. Subcommand handlers return this instead of
+/// panicking so malformed PNGs, oversized payloads and bad passwords all
+/// surface as a readable message rather than an `unwrap` backtrace.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Decoding(png::DecodingError),
+    Encoding(png::EncodingError),
+    Wav(hound::Error),
+    NoMessage,
+    WrongPassword,
+    DigestMismatch,
+    Capacity { needed: usize, available: usize },
+    InvalidHeader,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "invalid file: {e}"),
+            Error::Decoding(e) => write!(f, "invalid png: {e}"),
+            Error::Encoding(e) => write!(f, "failed to write png: {e}"),
+            Error::Wav(e) => write!(f, "wav error: {e}"),
+            Error::NoMessage => write!(f, "no message"),
+            Error::WrongPassword => write!(f, "wrong password or no message"),
+            Error::DigestMismatch => write!(f, "digest mismatch: corrupted carrier"),
+            Error::Capacity { needed, available } => write!(
+                f,
+                "message needs {needed} bytes but carrier holds {available}"
+            ),
+            Error::InvalidHeader => write!(f, "invalid or corrupted header: carrier not readable"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(e: png::DecodingError) -> Self {
+        Error::Decoding(e)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Self {
+        Error::Encoding(e)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(e: hound::Error) -> Self {
+        Error::Wav(e)
+    }
+}
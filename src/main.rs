@@ -1,10 +1,18 @@
 #![feature(try_blocks)]
 
+mod carrier;
+mod compress;
+mod crypto;
+mod digest;
+mod error;
+mod scatter;
+
+use carrier::Kind;
+use error::Error;
+
 use clap::{clap_app, AppSettings::SubcommandRequired};
-use itertools::Itertools;
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::BufWriter;
 use std::ops::Shl;
 use std::ops::{BitAnd, BitOr};
 use std::path::Path;
@@ -20,10 +28,29 @@ fn main() {
             (@arg file: +required "png file to use")
             (@arg message: +required "message to hide")
             (@arg output: -o --out +takes_value "output file")
+            (@arg password: -p --password +takes_value "encrypt the message with this password")
+            (@arg bits: -b --bits +takes_value "bits per channel to use (1, 2 or 4, default 2)")
+            (@arg key: -k --key +takes_value "scatter the payload across the carrier using this key")
+            (@arg format: -f --format +takes_value "carrier format: png or wav (default: by extension)")
+            (@arg compress: -c --compress "deflate-compress the message before embedding it")
         )
         (@subcommand decode =>
             (about: "encode message into png")
             (@arg file: +required "png file to use")
+            (@arg password: -p --password +takes_value "decrypt the message with this password")
+            (@arg key: -k --key +takes_value "key used to scatter the payload, if any")
+            (@arg format: -f --format +takes_value "carrier format: png or wav (default: by extension)")
+        )
+        (@subcommand embed =>
+            (about: "embed a binary file into a png or wav carrier")
+            (@arg file: +required "png or wav file to use")
+            (@arg payload: +required "file to embed")
+            (@arg output: -o --out +takes_value "output file")
+            (@arg password: -p --password +takes_value "encrypt the payload with this password")
+            (@arg bits: -b --bits +takes_value "bits per channel to use (1, 2 or 4, default 2)")
+            (@arg key: -k --key +takes_value "scatter the payload across the carrier using this key")
+            (@arg format: -f --format +takes_value "carrier format: png or wav (default: by extension)")
+            (@arg compress: -c --compress "deflate-compress the payload before embedding it")
         )
         (@subcommand debugmessage =>
             (about: "print bytestream for message")
@@ -36,8 +63,13 @@ fn main() {
     )
     .get_matches();
 
-    match matches.subcommand() {
-        ("decode", Some(matches)) => decode(matches.value_of("file").unwrap()),
+    let result = match matches.subcommand() {
+        ("decode", Some(matches)) => decode(
+            matches.value_of("file").unwrap(),
+            matches.value_of("password"),
+            matches.value_of("key"),
+            parse_format(matches.value_of("format"), matches.value_of("file").unwrap()),
+        ),
         ("encode", Some(matches)) => encode(
             matches.value_of("file").unwrap(),
             matches.value_of("message").unwrap(),
@@ -45,159 +77,394 @@ fn main() {
                 .value_of("output")
                 .or_else(|| matches.value_of("file"))
                 .unwrap(),
+            EncodeOptions {
+                password: matches.value_of("password"),
+                bits: parse_bits(matches.value_of("bits")),
+                key: matches.value_of("key"),
+                format: parse_format(matches.value_of("format"), matches.value_of("file").unwrap()),
+                compress: matches.is_present("compress"),
+            },
+        ),
+        ("embed", Some(matches)) => embed(
+            matches.value_of("file").unwrap(),
+            matches.value_of("payload").unwrap(),
+            matches
+                .value_of("output")
+                .or_else(|| matches.value_of("file"))
+                .unwrap(),
+            EncodeOptions {
+                password: matches.value_of("password"),
+                bits: parse_bits(matches.value_of("bits")),
+                key: matches.value_of("key"),
+                format: parse_format(matches.value_of("format"), matches.value_of("file").unwrap()),
+                compress: matches.is_present("compress"),
+            },
         ),
-        ("debugmessage", Some(matches)) => debugmessage(matches.value_of("message").unwrap()),
+        ("debugmessage", Some(matches)) => {
+            debugmessage(matches.value_of("message").unwrap());
+            Ok(())
+        }
         ("debugfile", Some(matches)) => debugfile(matches.value_of("file").unwrap()),
         _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
     }
 }
 
-struct Image {
-    bytes: Vec<u8>,
-    alpha: Option<Vec<u8>>,
-    info: png::OutputInfo,
-}
-
-fn decode(file: &str) {
-    match File::open(file) {
-        Ok(file) => {
-            let image = get_bytes(&file);
-            let extracted_bytes = extract_bytes(&image.bytes);
-            if check_magic_bytes(&extracted_bytes) {
-                let message = extracted_bytes
-                    .iter()
-                    .skip(8)
-                    .take(get_length(&extracted_bytes))
-                    .map(|e| *e as char)
-                    .collect::<String>();
-                println!("{message}");
-            } else {
-                eprintln!("no message");
+/// Number of low bits of each carrier byte used to encode the header,
+/// i.e. magic/flags/lengths/digest/bits-byte. The payload itself may use a
+/// different, self-described depth (see `--bits`), but the header has to be
+/// readable before that depth is known, so it is always fixed at 2.
+const HEADER_BITS: u8 = 2;
+
+fn parse_bits(arg: Option<&str>) -> u8 {
+    match arg {
+        None => 2,
+        Some(value) => match value.parse::<u8>() {
+            Ok(bits @ (1 | 2 | 4)) => bits,
+            _ => {
+                eprintln!("invalid --bits value \"{value}\", must be 1, 2 or 4");
+                std::process::exit(1);
             }
+        },
+    }
+}
+
+fn parse_format(arg: Option<&str>, file: &str) -> Kind {
+    match arg {
+        None => Kind::from_extension(file),
+        Some(value) => Kind::parse(value).unwrap_or_else(|| {
+            eprintln!("invalid --format value \"{value}\", must be png or wav");
+            std::process::exit(1);
+        }),
+    }
+}
+
+/// A payload ready to be embedded: either a plain UTF-8 message or the raw
+/// bytes of a file, together with the filename needed to restore it on `decode`.
+enum Payload {
+    Message(Vec<u8>),
+    File { name: String, bytes: Vec<u8> },
+}
+
+impl Payload {
+    fn flags(&self) -> u8 {
+        match self {
+            Payload::Message(_) => 0,
+            Payload::File { .. } => 1,
         }
-        Err(_) => eprintln!("invalid file"),
-    }
-}
-
-fn encode(file: &str, message: &str, output: &str) {
-    match File::open(file) {
-        Ok(file) => {
-            let image = get_bytes(&file);
-            println!("encoding message \"{message}\" into file {output}");
-            let path = Path::new(output);
-            let file = File::create(path).unwrap();
-            let w = BufWriter::new(file);
-
-            let mut encoder = png::Encoder::new(w, image.info.width, image.info.height);
-            encoder.set_color(image.info.color_type);
-            encoder.set_depth(png::BitDepth::Eight);
-            let mut writer = encoder.write_header().unwrap();
-
-            writer
-                .write_image_data(&add_alpha(
-                    inject_message(&image.bytes, message),
-                    image.alpha,
-                ))
-                .unwrap();
+    }
+
+    fn name_bytes(&self) -> Vec<u8> {
+        match self {
+            Payload::Message(_) => Vec::new(),
+            Payload::File { name, .. } => name.as_bytes().to_vec(),
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self {
+            Payload::Message(bytes) => bytes,
+            Payload::File { bytes, .. } => bytes,
         }
-        Err(_) => eprintln!("invalid file"),
     }
 }
 
+fn decode(file: &str, password: Option<&str>, key: Option<&str>, format: Kind) -> Result<(), Error> {
+    let file = File::open(file)?;
+    let image = carrier::load(&file, format)?;
+    let header_bytes = extract_bytes(&image.bytes, HEADER_BITS);
+    if !check_magic_bytes(&header_bytes) {
+        return Err(Error::NoMessage);
+    }
+    // A carrier can match the magic bytes yet be too short to even hold the
+    // fixed-size flags/name_len fields that follow them.
+    if header_bytes.len() < 9 {
+        return Err(Error::InvalidHeader);
+    }
+
+    let flags = header_bytes[4];
+    let name_len = get_name_length(&header_bytes) as usize;
+    // name_len comes straight from the (untrusted) carrier, so bound it
+    // against the extracted header before indexing with it below.
+    if header_bytes.len() < HEADER_LEN + name_len {
+        return Err(Error::InvalidHeader);
+    }
+    let message_len = get_length(&header_bytes);
+    let stored_digest = get_digest(&header_bytes, name_len);
+    let bits = get_bits(&header_bytes, name_len);
+    if !matches!(bits, 1 | 2 | 4) {
+        return Err(Error::InvalidHeader);
+    }
+
+    let header_byte_count = HEADER_LEN + name_len;
+    let data_carrier_offset = header_byte_count * (8 / HEADER_BITS as usize);
+    if data_carrier_offset > image.bytes.len() {
+        return Err(Error::InvalidHeader);
+    }
+    let group_count = message_len * (8 / bits as usize);
+    let raw = match key {
+        Some(key) => extract_scattered(&image.bytes, data_carrier_offset, bits, key, group_count),
+        None => extract_bytes(&image.bytes[data_carrier_offset..], bits)
+            .into_iter()
+            .take(message_len)
+            .collect::<Vec<u8>>(),
+    };
+
+    if digest::digest(&raw) != stored_digest {
+        return Err(Error::DigestMismatch);
+    }
+
+    let decrypted = if flags & 0x2 != 0 {
+        let password = password.ok_or(Error::WrongPassword)?;
+        crypto::decrypt(&raw, password).ok_or(Error::WrongPassword)?
+    } else {
+        raw
+    };
+
+    let message = if flags & 0x4 != 0 {
+        compress::decompress(&decrypted)?
+    } else {
+        decrypted
+    };
+
+    if flags & 0x1 != 0 {
+        let name = String::from_utf8_lossy(&header_bytes[9..9 + name_len]).to_string();
+        // The name came from the carrier's header, which is untrusted: strip
+        // any directory components so a crafted "../../etc/passwd" can't
+        // write outside the current directory.
+        let name = Path::new(&name)
+            .file_name()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_else(|| "embedded_file".to_string());
+        std::fs::write(&name, &message)?;
+        println!("wrote embedded file to {name}");
+    } else {
+        println!("{}", String::from_utf8_lossy(&message));
+    }
+    Ok(())
+}
+
+/// Shared knobs for `encode`/`embed`: how to protect the payload and which
+/// carrier to put it in. Bundled into one struct because the two functions
+/// otherwise take the same five arguments: `format` picks the carrier to
+/// load and save, the other four pass through to `inject_message`.
+struct EncodeOptions<'a> {
+    password: Option<&'a str>,
+    bits: u8,
+    key: Option<&'a str>,
+    format: Kind,
+    compress: bool,
+}
+
+fn encode(file: &str, message: &str, output: &str, opts: EncodeOptions) -> Result<(), Error> {
+    let file = File::open(file)?;
+    let image = carrier::load(&file, opts.format)?;
+    println!("encoding message \"{message}\" into file {output}");
+    let data = inject_message(
+        &image.bytes,
+        &Payload::Message(message.as_bytes().to_vec()),
+        opts.password,
+        opts.bits,
+        opts.key,
+        opts.compress,
+    )?;
+    carrier::save(output, &image, &data, opts.format)
+}
+
+fn embed(file: &str, payload: &str, output: &str, opts: EncodeOptions) -> Result<(), Error> {
+    let file = File::open(file)?;
+    let image = carrier::load(&file, opts.format)?;
+    let name = Path::new(payload)
+        .file_name()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| payload.to_string());
+    let bytes = std::fs::read(payload)?;
+    println!("embedding file \"{payload}\" into file {output}");
+    let data = inject_message(
+        &image.bytes,
+        &Payload::File { name, bytes },
+        opts.password,
+        opts.bits,
+        opts.key,
+        opts.compress,
+    )?;
+    carrier::save(output, &image, &data, opts.format)
+}
+
 fn check_magic_bytes(bytes: &[u8]) -> bool {
     bytes.iter().take(4).map(|e| *e as char).collect::<String>() == "BHTM"
 }
 
+fn get_name_length(bytes: &[u8]) -> u32 {
+    let int_bytes = &bytes[5..9];
+    u32::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
 fn get_length(bytes: &[u8]) -> usize {
-    let int_bytes = &bytes[4..8];
+    let name_len = get_name_length(bytes) as usize;
+    let int_bytes = &bytes[9 + name_len..13 + name_len];
     u32::from_be_bytes(int_bytes.try_into().unwrap()) as usize
 }
 
-fn extract_bytes(rgb_bytes: &[u8]) -> Vec<u8> {
-    let mut extracted = Vec::with_capacity(rgb_bytes.len() / 4);
-    for chunk in rgb_bytes.chunks(4) {
+fn get_digest(bytes: &[u8], name_len: usize) -> [u8; 4] {
+    bytes[13 + name_len..17 + name_len].try_into().unwrap()
+}
+
+/// Header byte count up to and including the bits-byte, excluding the
+/// variable-length filename.
+const HEADER_LEN: usize = 18;
+
+fn get_bits(bytes: &[u8], name_len: usize) -> u8 {
+    bytes[17 + name_len]
+}
+
+fn mask(bits: u8) -> u8 {
+    ((1u16 << bits) - 1) as u8
+}
+
+fn extract_bytes(rgb_bytes: &[u8], bits: u8) -> Vec<u8> {
+    let group_size = 8 / bits;
+    let mask = mask(bits);
+    let mut extracted = Vec::with_capacity(rgb_bytes.len() / group_size as usize);
+    for chunk in rgb_bytes.chunks(group_size as usize) {
         let _: Option<()> = try {
             let mut chunk = chunk.iter();
-            let v1 = (chunk.next()? & 0x3).shl(6);
-            let v2 = (chunk.next()? & 0x3).shl(4);
-            let v3 = (chunk.next()? & 0x3).shl(2);
-            let v4 = (chunk.next()? & 0x3).shl(0);
-            extracted.push(v1 | v2 | v3 | v4);
+            let mut byte = 0u8;
+            for i in 0..group_size {
+                byte |= (chunk.next()? & mask).shl(8 - bits * (i + 1));
+            }
+            extracted.push(byte);
         };
     }
     extracted
 }
 
-fn get_bytes(file: &File) -> Image {
-    let mut decoder = png::Decoder::new(file);
-    decoder.set_transformations(png::Transformations::normalize_to_color8());
-    let mut reader = decoder.read_info().unwrap();
-
-    let buffersize = reader.output_buffer_size();
-
-    let mut bytes = vec![0; buffersize];
-    let info = reader.next_frame(&mut bytes).unwrap();
-
-    let mut alpha = None;
-
-    if info.color_type == png::ColorType::Rgba {
-        alpha = Some(
-            bytes
-                .iter()
-                .enumerate()
-                .filter(|(i, _)| i % 4 == 3)
-                .map(|(_, e)| *e)
-                .collect(),
-        );
-        bytes = bytes
-            .into_iter()
-            .enumerate()
-            .filter(|(i, _)| i % 4 != 3)
-            .map(|(_, e)| e)
-            .collect();
-    }
-    Image { bytes, alpha, info }
+/// Recombines `group_size = 8 / bits` raw (already-masked) groups per output
+/// byte, in order. Used by the scattered read path below; `extract_bytes`
+/// does the same recombination inline on unmasked carrier bytes.
+fn recombine_groups(groups: &[u8], bits: u8) -> Vec<u8> {
+    let group_size = (8 / bits) as usize;
+    groups
+        .chunks(group_size)
+        .filter(|chunk| chunk.len() == group_size)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0u8, |byte, (i, v)| {
+                byte | v.shl(8 - bits * (i as u8 + 1))
+            })
+        })
+        .collect()
 }
 
-fn split_bytes(bytes: &[u8]) -> impl Iterator<Item = u8> + '_ {
-    bytes.iter().flat_map(|e| {
-        [
-            e.rotate_right(6) & 0x3,
-            e.rotate_right(4) & 0x3,
-            e.rotate_right(2) & 0x3,
-            e.rotate_right(0) & 0x3,
-        ]
-    })
+/// Reads `group_count` payload groups back out of `carrier[base_offset..]`
+/// using the same keyed permutation `write_scattered` wrote them with.
+fn extract_scattered(carrier: &[u8], base_offset: usize, bits: u8, key: &str, group_count: usize) -> Vec<u8> {
+    let region_len = carrier.len() - base_offset;
+    let permutation = scatter::permutation(key, region_len);
+    let groups: Vec<u8> = permutation
+        .iter()
+        .take(group_count)
+        .map(|&index| carrier[base_offset + index] & mask(bits))
+        .collect();
+    recombine_groups(&groups, bits)
 }
 
-fn add_alpha(bytes: Vec<u8>, alpha: Option<Vec<u8>>) -> Vec<u8> {
-    match alpha {
-        Some(alpha) => bytes
-            .into_iter()
-            .chunks(3)
-            .into_iter()
-            .interleave(alpha.into_iter().chunks(1).into_iter())
-            .flatten()
-            .collect(),
-        None => bytes,
+/// Writes `groups` (one per carrier byte, low `bits` bits) into
+/// `carrier[base_offset..]` at positions from a keyed Fisher–Yates
+/// permutation instead of sequentially, so the payload leaves no
+/// statistical signature in the carrier's leading region.
+fn write_scattered(carrier: &mut [u8], base_offset: usize, bits: u8, key: &str, groups: &[u8]) {
+    let region_len = carrier.len() - base_offset;
+    let permutation = scatter::permutation(key, region_len);
+    for (&index, &group) in permutation.iter().zip(groups) {
+        let carrier_index = base_offset + index;
+        carrier[carrier_index] = (carrier[carrier_index] & !mask(bits)) | group;
     }
 }
 
-fn inject_message(bytes: &[u8], message: &str) -> Vec<u8> {
-    let lenght = (message.len() as u32).to_be_bytes().to_vec();
+fn split_bytes(bytes: &[u8], bits: u8) -> impl Iterator<Item = u8> + '_ {
+    let group_size = 8 / bits;
+    bytes
+        .iter()
+        .flat_map(move |e| (0..group_size).map(move |i| (e >> (8 - bits * (i + 1))) & mask(bits)))
+}
+
+fn inject_message(
+    bytes: &[u8],
+    payload: &Payload,
+    password: Option<&str>,
+    bits: u8,
+    key: Option<&str>,
+    compress: bool,
+) -> Result<Vec<u8>, Error> {
     let magic = b"BHTM".to_vec();
+    let name_bytes = payload.name_bytes();
+    let name_len = (name_bytes.len() as u32).to_be_bytes().to_vec();
 
-    let full_message = split_bytes(&magic)
-        .chain(split_bytes(&lenght))
-        .chain(split_bytes(message.as_bytes()))
-        .map(Some)
-        .chain(std::iter::repeat(None));
+    let mut flags = payload.flags();
+    let payload_data = if compress {
+        match compress::compress(payload.data()) {
+            Some(compressed) => {
+                flags |= 0x4;
+                compressed
+            }
+            None => payload.data().to_vec(),
+        }
+    } else {
+        payload.data().to_vec()
+    };
+    let data = match password {
+        Some(password) => {
+            flags |= 0x2;
+            crypto::encrypt(&payload_data, password)
+        }
+        None => payload_data,
+    };
+    let data_len = (data.len() as u32).to_be_bytes().to_vec();
+    let data_digest = digest::digest(&data).to_vec();
+    let bits_byte = vec![bits];
+    let flags_byte = [flags];
 
-    bytes
+    let header_byte_count = HEADER_LEN + name_bytes.len();
+    let needed = header_byte_count * (8 / HEADER_BITS as usize) + data.len() * (8 / bits as usize);
+    let available = bytes.len();
+    if needed > available {
+        return Err(Error::Capacity { needed, available });
+    }
+
+    // The header is always written sequentially at `HEADER_BITS` so `decode`
+    // can read the chosen payload depth back out before it needs to know it.
+    // Whether a scatter key was used is not recorded in the header.
+    let header = split_bytes(&magic, HEADER_BITS)
+        .chain(split_bytes(&flags_byte, HEADER_BITS))
+        .chain(split_bytes(&name_len, HEADER_BITS))
+        .chain(split_bytes(&name_bytes, HEADER_BITS))
+        .chain(split_bytes(&data_len, HEADER_BITS))
+        .chain(split_bytes(&data_digest, HEADER_BITS))
+        .chain(split_bytes(&bits_byte, HEADER_BITS));
+
+    let header_message = header.map(Some).chain(std::iter::repeat(None));
+    let mut out: Vec<u8> = bytes
         .iter()
-        .zip(full_message)
-        .map(|(byte, message)| message.map_or(*byte, |m| byte.bitand(0b1111_1100).bitor(m)))
-        .collect()
+        .zip(header_message)
+        .map(|(byte, group)| group.map_or(*byte, |m| byte.bitand(!mask(HEADER_BITS)).bitor(m)))
+        .collect();
+
+    let data_carrier_offset = header_byte_count * (8 / HEADER_BITS as usize);
+    let data_groups: Vec<u8> = split_bytes(&data, bits).collect();
+    match key {
+        Some(key) => write_scattered(&mut out, data_carrier_offset, bits, key, &data_groups),
+        None => {
+            for (carrier, group) in out[data_carrier_offset..].iter_mut().zip(&data_groups) {
+                *carrier = (*carrier & !mask(bits)) | group;
+            }
+        }
+    }
+
+    Ok(out)
 }
 
 fn debugmessage(message: &str) {
@@ -207,9 +474,9 @@ fn debugmessage(message: &str) {
 
     println!(
         "{:?}",
-        split_bytes(&magic)
-            .chain(split_bytes(&lenght))
-            .chain(split_bytes(message.as_bytes()))
+        split_bytes(&magic, HEADER_BITS)
+            .chain(split_bytes(&lenght, HEADER_BITS))
+            .chain(split_bytes(message.as_bytes(), HEADER_BITS))
             .collect::<Vec<u8>>()
     );
 
@@ -223,24 +490,21 @@ fn debugmessage(message: &str) {
     );
 }
 
-fn debugfile(file: &str) {
-    match File::open(file) {
-        Ok(file) => {
-            let image = get_bytes(&file);
-            for byte in image.bytes.iter().take(10) {
-                println!("{:2x} ", byte);
-            }
-            for row in extract_bytes(&image.bytes).chunks(8) {
-                for c in row {
-                    print!("{:2x} ", c);
-                }
-                print!("  ");
-                for c in row {
-                    print!("{}", (*c as char).escape_default());
-                }
-                println!();
-            }
+fn debugfile(path: &str) -> Result<(), Error> {
+    let file = File::open(path)?;
+    let image = carrier::load(&file, Kind::from_extension(path))?;
+    for byte in image.bytes.iter().take(10) {
+        println!("{:2x} ", byte);
+    }
+    for row in extract_bytes(&image.bytes, HEADER_BITS).chunks(8) {
+        for c in row {
+            print!("{:2x} ", c);
+        }
+        print!("  ");
+        for c in row {
+            print!("{}", (*c as char).escape_default());
         }
-        Err(_) => eprintln!("invalid file"),
+        println!();
     }
+    Ok(())
 }
@@ -0,0 +1,48 @@
+/// SplitMix64, used only to turn a `--key` string into a reproducible stream
+/// of indices for the Fisher–Yates shuffle below. Not cryptographically
+/// secure on its own; the security this buys is "you need the key to find
+/// the bits", not "you can't recover the key from the permutation".
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+fn seed_from_key(key: &str) -> u64 {
+    // FNV-1a; just needs to spread the key's bytes over 64 bits, not resist attack.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+/// A deterministic Fisher–Yates permutation of `0..len`, reproducible from
+/// `key` alone so `encode` and `decode` derive the same carrier-channel
+/// ordering without storing it anywhere.
+pub fn permutation(key: &str, len: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..len).collect();
+    let mut rng = SplitMix64::new(seed_from_key(key));
+    for i in (1..len).rev() {
+        let j = rng.below(i + 1);
+        indices.swap(i, j);
+    }
+    indices
+}